@@ -1,48 +1,156 @@
-#[derive(Debug, Clone, PartialEq)]
-pub enum DocCore {
+use std::fmt;
+use std::io;
+use std::io::Write;
+use std::rc::Rc;
+
+use unicode_width::UnicodeWidthStr;
+
+#[derive(Clone)]
+pub enum DocCore<A = ()> {
     Nil,
-    Append(Box<DocCore>, Box<DocCore>),
-    Nest(i64, Box<DocCore>),
+    Append(Box<DocCore<A>>, Box<DocCore<A>>),
+    Nest(i64, Box<DocCore<A>>),
     Text(String),
     Line,
-    Union(Box<DocCore>, Box<DocCore>),
+    Union(Box<DocCore<A>>, Box<DocCore<A>>),
+    // Carries an annotation `A` through rendering, e.g. a style tag or a
+    // source span, so a consumer can tell which output bytes came from
+    // which annotated subtree. See `render_annotated`.
+    Annotate(A, Box<DocCore<A>>),
+    // Produces a document from the current output column; used to build
+    // `align` so continuation lines track the cursor rather than a fixed
+    // nesting offset.
+    Column(Rc<dyn Fn(i64) -> DocCore<A>>),
+    // Produces a document from the current nesting/indent level.
+    Nesting(Rc<dyn Fn(i64) -> DocCore<A>>),
+}
+
+impl<A: fmt::Debug> fmt::Debug for DocCore<A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use DocCore::*;
+        match self {
+            Nil => write!(f, "Nil"),
+            Append(x, y) => f.debug_tuple("Append").field(x).field(y).finish(),
+            Nest(i, x) => f.debug_tuple("Nest").field(i).field(x).finish(),
+            Text(s) => f.debug_tuple("Text").field(s).finish(),
+            Line => write!(f, "Line"),
+            Union(x, y) => f.debug_tuple("Union").field(x).field(y).finish(),
+            Annotate(a, x) => f.debug_tuple("Annotate").field(a).field(x).finish(),
+            Column(_) => write!(f, "Column(..)"),
+            Nesting(_) => write!(f, "Nesting(..)"),
+        }
+    }
+}
+
+// `Column`/`Nesting` hold a closure, which has no structural notion of
+// equality; two of them compare equal only when they're the same `Rc`.
+impl<A: PartialEq> PartialEq for DocCore<A> {
+    fn eq(&self, other: &Self) -> bool {
+        use DocCore::*;
+        match (self, other) {
+            (Nil, Nil) => true,
+            (Append(x1, y1), Append(x2, y2)) => x1 == x2 && y1 == y2,
+            (Nest(i1, x1), Nest(i2, x2)) => i1 == i2 && x1 == x2,
+            (Text(s1), Text(s2)) => s1 == s2,
+            (Line, Line) => true,
+            (Union(x1, y1), Union(x2, y2)) => x1 == x2 && y1 == y2,
+            (Annotate(a1, x1), Annotate(a2, x2)) => a1 == a2 && x1 == x2,
+            (Column(f1), Column(f2)) => Rc::ptr_eq(f1, f2),
+            (Nesting(f1), Nesting(f2)) => Rc::ptr_eq(f1, f2),
+            _ => false,
+        }
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Doc {
+pub enum Doc<A = ()> {
     Nil,
-    Text(String, Box<Doc>),
-    Line(i64, Box<Doc>),
+    Text(String, Box<Doc<A>>),
+    Line(i64, Box<Doc<A>>),
+    PushAnn(A, Box<Doc<A>>),
+    PopAnn(Box<Doc<A>>),
+}
+
+// An item of `be`'s work list: either a doc to lay out at a given
+// indentation, or a marker to close the annotation most recently opened
+// by an `Annotate` whose contents have just finished.
+#[derive(Clone)]
+enum Work<A> {
+    Doc(i64, DocCore<A>),
+    Pop,
 }
 
 // TODO: move to pretty module.
 
-pub fn nil() -> DocCore {
+pub fn nil<A>() -> DocCore<A> {
     DocCore::Nil
 }
 
 // append is right associative.
-pub fn append(x: DocCore, y: DocCore) -> DocCore {
+pub fn append<A>(x: DocCore<A>, y: DocCore<A>) -> DocCore<A> {
     DocCore::Append(x.into(), y.into())
 }
 
-pub fn nest(i: i64, x: DocCore) -> DocCore {
+pub fn nest<A>(i: i64, x: DocCore<A>) -> DocCore<A> {
     DocCore::Nest(i, x.into())
 }
 
-pub fn text(s: String) -> DocCore {
+pub fn text<A>(s: String) -> DocCore<A> {
     DocCore::Text(s)
 }
 
-pub fn line() -> DocCore {
+pub fn line<A>() -> DocCore<A> {
     DocCore::Line
 }
 
-pub fn group(x: DocCore) -> DocCore {
+pub fn annotate<A>(a: A, x: DocCore<A>) -> DocCore<A> {
+    DocCore::Annotate(a, x.into())
+}
+
+pub fn column<A, F>(f: F) -> DocCore<A>
+where
+    F: Fn(i64) -> DocCore<A> + 'static,
+{
+    DocCore::Column(Rc::new(f))
+}
+
+pub fn nesting<A, F>(f: F) -> DocCore<A>
+where
+    F: Fn(i64) -> DocCore<A> + 'static,
+{
+    DocCore::Nesting(Rc::new(f))
+}
+
+// Sets the nesting level of `x` to the current output column for as long as
+// `x` is being laid out, so its continuation lines line up under wherever
+// `align` itself started rather than at a fixed offset.
+pub fn align<A: Clone + 'static>(x: DocCore<A>) -> DocCore<A> {
+    column(move |k| {
+        let x = x.clone();
+        nesting(move |i| nest(k - i, x.clone()))
+    })
+}
+
+// `nest(i, x)` aligned to the current column: continuation lines sit `i`
+// columns past wherever `hang` started, not past the enclosing nest level.
+pub fn hang<A: Clone + 'static>(i: i64, x: DocCore<A>) -> DocCore<A> {
+    align(nest(i, x))
+}
+
+// Prepends `i` spaces, then aligns, so `x` starts indented and its own
+// continuation lines line up under that indent. The spaces sit outside the
+// `align`, not inside it — otherwise `align` would capture the column
+// `indent` itself started at, and continuation lines would line up one
+// `i` short of where `x` was actually printed.
+pub fn indent<A: Clone + 'static>(i: i64, x: DocCore<A>) -> DocCore<A> {
+    append(text(copy(i, " ")), align(x))
+}
+
+pub fn group<A: Clone + 'static>(x: DocCore<A>) -> DocCore<A> {
     DocCore::Union(flatten(x.clone()).into(), x.clone().into())
 }
 
-pub fn flatten(x: DocCore) -> DocCore {
+pub fn flatten<A: Clone + 'static>(x: DocCore<A>) -> DocCore<A> {
     use DocCore::*;
     match x {
         Nil => Nil,
@@ -51,15 +159,23 @@ pub fn flatten(x: DocCore) -> DocCore {
         Text(s) => Text(s),
         Line => Text(String::from(" ")),
         Union(x, _y) => flatten(*x),
+        Annotate(a, x) => Annotate(a, flatten(*x).into()),
+        // Not yet resolved to a concrete document, so wrap them to flatten
+        // whatever they produce once the column/nesting they were waiting
+        // on is known.
+        Column(f) => column(move |k| flatten(f(k))),
+        Nesting(f) => nesting(move |i| flatten(f(i))),
     }
 }
 
-pub fn layout(x: Doc) -> String {
+pub fn layout<A>(x: Doc<A>) -> String {
     use Doc::*;
     match x {
         Nil => String::from(""),
         Text(s, x) => format!("{}{}", s, layout(*x)),
         Line(i, x) => format!("\n{}{}", copy(i, " "), layout(*x)),
+        PushAnn(_a, x) => layout(*x),
+        PopAnn(x) => layout(*x),
     }
 }
 
@@ -70,60 +186,355 @@ pub fn copy(i: i64, x: &str) -> String {
         .join("")
 }
 
-pub fn best(w: i64, k: i64, x: DocCore) -> Doc {
-    be(w, k, &[(0, x)])
+// Display-column width rather than byte count, so multibyte text (accents,
+// CJK, emoji) advances the column counters `be`/`fits` use by the same
+// amount a terminal would actually render it.
+fn text_width(s: &str) -> i64 {
+    s.width() as i64
+}
+
+pub fn best<A: Clone>(w: i64, ribbon: i64, k: i64, x: DocCore<A>) -> Doc<A> {
+    be(w, ribbon, k, k, &[Work::Doc(0, x)])
 }
 
-pub fn be(w: i64, k: i64, xs: &[(i64, DocCore)]) -> Doc {
+// `c0` is the column at which the current line's printable content began
+// (reset to the indent whenever a `Line` is emitted), so `k - c0` is how
+// much of the ribbon the current line has used so far.
+fn be<A: Clone>(w: i64, ribbon: i64, k: i64, c0: i64, xs: &[Work<A>]) -> Doc<A> {
     use DocCore::*;
     match xs.split_first() {
         None => Doc::Nil,
-        Some(((_i, Nil), z)) => be(w, k, &z),
-        Some(((i, Append(x, y)), z)) => {
-            let mut zs = vec![(*i, *x.clone()), (*i, *y.clone())];
+        Some((Work::Pop, z)) => Doc::PopAnn(be(w, ribbon, k, c0, z).into()),
+        Some((Work::Doc(_i, Nil), z)) => be(w, ribbon, k, c0, z),
+        Some((Work::Doc(i, Append(x, y)), z)) => {
+            let mut zs = vec![Work::Doc(*i, *x.clone()), Work::Doc(*i, *y.clone())];
             zs.extend_from_slice(z);
-            be(w, k, &zs)
+            be(w, ribbon, k, c0, &zs)
         }
-        Some(((i, Nest(j, x)), z)) => {
-            let mut zs = vec![(i + j, *x.clone())];
+        Some((Work::Doc(i, Nest(j, x)), z)) => {
+            let mut zs = vec![Work::Doc(i + j, *x.clone())];
             zs.extend_from_slice(z);
-            be(w, k, &zs)
+            be(w, ribbon, k, c0, &zs)
+        }
+        Some((Work::Doc(_i, Text(s)), z)) => {
+            Doc::Text(s.clone(), be(w, ribbon, k + text_width(s), c0, z).into())
         }
-        Some(((_i, Text(s)), z)) => Doc::Text(s.clone(), be(w, k + s.len() as i64, z).into()),
-        Some(((i, Line), z)) => Doc::Line(*i, be(w, *i, z).into()),
-        Some(((i, Union(x, y)), z)) => {
-            let mut zs1 = vec![(*i, *x.clone())];
-            let mut zs2 = vec![(*i, *y.clone())];
+        Some((Work::Doc(i, Line), z)) => Doc::Line(*i, be(w, ribbon, *i, *i, z).into()),
+        Some((Work::Doc(i, Union(x, y)), z)) => {
+            let mut zs1 = vec![Work::Doc(*i, *x.clone())];
+            let mut zs2 = vec![Work::Doc(*i, *y.clone())];
             zs1.extend_from_slice(z);
             zs2.extend_from_slice(z);
-            better(w, k, be(w, k, &zs1), be(w, k, &zs2))
+            better(
+                w,
+                ribbon,
+                k,
+                c0,
+                be(w, ribbon, k, c0, &zs1),
+                be(w, ribbon, k, c0, &zs2),
+            )
+        }
+        Some((Work::Doc(i, Annotate(a, x)), z)) => {
+            let mut zs = vec![Work::Doc(*i, *x.clone()), Work::Pop];
+            zs.extend_from_slice(z);
+            Doc::PushAnn(a.clone(), be(w, ribbon, k, c0, &zs).into())
+        }
+        Some((Work::Doc(i, Column(f)), z)) => {
+            let mut zs = vec![Work::Doc(*i, f(k))];
+            zs.extend_from_slice(z);
+            be(w, ribbon, k, c0, &zs)
+        }
+        Some((Work::Doc(i, Nesting(f)), z)) => {
+            let mut zs = vec![Work::Doc(*i, f(*i))];
+            zs.extend_from_slice(z);
+            be(w, ribbon, k, c0, &zs)
         }
     }
 }
 
-pub fn better(w: i64, k: i64, x: Doc, y: Doc) -> Doc {
-    if fits(w - k, x.clone()) {
+pub fn better<A: Clone>(w: i64, ribbon: i64, k: i64, c0: i64, x: Doc<A>, y: Doc<A>) -> Doc<A> {
+    if fits(w - k, ribbon - (k - c0), x.clone()) {
         x
     } else {
         y
     }
 }
 
-pub fn fits(w: i64, x: Doc) -> bool {
+pub fn fits<A: Clone>(w: i64, ribbon: i64, x: Doc<A>) -> bool {
     // NOTE: if we were using isize we'd keep this condition.
-    if w < 0 {
+    if w < 0 || ribbon < 0 {
         return false;
     }
     use Doc::*;
     match x {
         Nil => true,
-        Text(s, x) => fits(w - s.len() as i64, *x.clone()),
+        Text(s, x) => fits(w - text_width(&s), ribbon - text_width(&s), *x.clone()),
         Line(_i, _x) => true,
+        PushAnn(_a, x) => fits(w, ribbon, *x.clone()),
+        PopAnn(x) => fits(w, ribbon, *x.clone()),
+    }
+}
+
+// A work-stack entry: most nodes are borrowed straight out of the original
+// tree, but `Column`/`Nesting` only produce a concrete `DocCore` once the
+// current column/indent is known, so those have to be owned.
+enum Node<'a, A> {
+    Ref(&'a DocCore<A>),
+    Owned(Box<DocCore<A>>),
+}
+
+impl<'a, A> Node<'a, A> {
+    fn as_doc(&self) -> &DocCore<A> {
+        match self {
+            Node::Ref(d) => d,
+            Node::Owned(d) => d,
+        }
+    }
+}
+
+// Streaming renderer: writes directly to `out` as group decisions are made,
+// never materializing a `Doc`. The work stack holds borrowed `&DocCore`
+// nodes rather than clones. The lookahead in `fits_ahead` does clone: the
+// flat branch of each group, and each pending stack entry it reaches. It
+// can also backtrack, so laying out a document is not linear in the number
+// of nodes.
+pub fn render<A: Clone, W: Write>(
+    w: i64,
+    ribbon: i64,
+    doc: &DocCore<A>,
+    out: &mut W,
+) -> io::Result<()> {
+    render_stack(w, ribbon, 0, 0, vec![(0, Node::Ref(doc))], out)
+}
+
+fn render_stack<A: Clone, W: Write>(
+    w: i64,
+    ribbon: i64,
+    mut k: i64,
+    mut c0: i64,
+    mut stack: Vec<(i64, Node<A>)>,
+    out: &mut W,
+) -> io::Result<()> {
+    use DocCore::*;
+    while let Some((i, node)) = stack.pop() {
+        match node {
+            Node::Ref(doc) => match doc {
+                Nil => {}
+                Append(x, y) => {
+                    stack.push((i, Node::Ref(y)));
+                    stack.push((i, Node::Ref(x)));
+                }
+                Nest(j, x) => stack.push((i + j, Node::Ref(x))),
+                Text(s) => {
+                    out.write_all(s.as_bytes())?;
+                    k += text_width(s);
+                }
+                Line => {
+                    out.write_all(b"\n")?;
+                    write_indent(out, i)?;
+                    k = i;
+                    c0 = i;
+                }
+                Union(x, y) => {
+                    if fits_ahead(w, ribbon, k, c0, i, x, &stack) {
+                        stack.push((i, Node::Ref(x)));
+                    } else {
+                        stack.push((i, Node::Ref(y)));
+                    }
+                }
+                Annotate(_a, x) => stack.push((i, Node::Ref(x))),
+                Column(f) => stack.push((i, Node::Owned(Box::new(f(k))))),
+                Nesting(f) => stack.push((i, Node::Owned(Box::new(f(i))))),
+            },
+            Node::Owned(doc) => match *doc {
+                Nil => {}
+                Append(x, y) => {
+                    stack.push((i, Node::Owned(y)));
+                    stack.push((i, Node::Owned(x)));
+                }
+                Nest(j, x) => stack.push((i + j, Node::Owned(x))),
+                Text(s) => {
+                    out.write_all(s.as_bytes())?;
+                    k += text_width(&s);
+                }
+                Line => {
+                    out.write_all(b"\n")?;
+                    write_indent(out, i)?;
+                    k = i;
+                    c0 = i;
+                }
+                Union(x, y) => {
+                    if fits_ahead(w, ribbon, k, c0, i, &x, &stack) {
+                        stack.push((i, Node::Owned(x)));
+                    } else {
+                        stack.push((i, Node::Owned(y)));
+                    }
+                }
+                Annotate(_a, x) => stack.push((i, Node::Owned(x))),
+                Column(f) => stack.push((i, Node::Owned(Box::new(f(k))))),
+                Nesting(f) => stack.push((i, Node::Owned(Box::new(f(i))))),
+            },
+        }
     }
+    Ok(())
+}
+
+fn write_indent<W: Write>(out: &mut W, i: i64) -> io::Result<()> {
+    for _ in 0..i {
+        out.write_all(b" ")?;
+    }
+    Ok(())
+}
+
+// Bounded fits check: scans `doc`, then the pending `rest` of the stack
+// (top-of-stack at the *end*, same as `render_stack`'s own `Vec::pop`
+// convention — walking `rest` in any other order checks the continuation
+// out of sequence), stopping as soon as the page or ribbon budget goes
+// negative or a hard `Line` is reached. A `Union` met along the way
+// (whether still in `doc` or further down in `rest`) is resolved the same
+// way `better` would resolve it for real — by trying its flat branch
+// against the remaining budget before the next real line break, falling
+// back to the broken branch only if that fails — rather than assumed flat
+// outright. Otherwise a breakable group further down the stack gets
+// counted as flat even when the real renderer would go on to break it,
+// which forces groups earlier in the stack to break that shouldn't.
+fn fits_ahead<'a, A: Clone>(
+    w: i64,
+    ribbon: i64,
+    k: i64,
+    c0: i64,
+    i: i64,
+    doc: &DocCore<A>,
+    rest: &[(i64, Node<'a, A>)],
+) -> bool {
+    fits_ahead_scan(w, ribbon, k, c0, vec![(i, doc.clone())], rest)
+}
+
+// A `Union` resolved mid-scan by trying its flat branch `x`, recording
+// enough to fall back to its broken branch `y` from the same point if the
+// flat branch later runs out of budget. Restoring from a `Choice` only
+// truncates `todo` and rewinds the `rest` slice pointer, so a failed flat
+// trial backtracks to exactly where it diverged instead of re-scanning
+// `todo`/`rest` from the top. Everything after the `Choice` is scanned
+// again on its broken branch, groups included, so a group further down
+// can be walked once for each combination of earlier choices tried.
+struct Choice<'r, 'a, A> {
+    todo_len: usize,
+    rest: &'r [(i64, Node<'a, A>)],
+    k: i64,
+    alt: (i64, DocCore<A>),
+}
+
+fn fits_ahead_scan<'r, 'a, A: Clone>(
+    w: i64,
+    ribbon: i64,
+    mut k: i64,
+    c0: i64,
+    mut todo: Vec<(i64, DocCore<A>)>,
+    mut rest: &'r [(i64, Node<'a, A>)],
+) -> bool {
+    use DocCore::*;
+    let mut choices: Vec<Choice<'r, 'a, A>> = Vec::new();
+    loop {
+        if w - k < 0 || ribbon - (k - c0) < 0 {
+            match choices.pop() {
+                Some(c) => {
+                    todo.truncate(c.todo_len);
+                    rest = c.rest;
+                    k = c.k;
+                    todo.push(c.alt);
+                    continue;
+                }
+                None => return false,
+            }
+        }
+        let (i, doc) = match todo.pop() {
+            Some(item) => item,
+            None => match rest.split_last() {
+                Some((last, init)) => {
+                    rest = init;
+                    (last.0, last.1.as_doc().clone())
+                }
+                None => return true,
+            },
+        };
+        match doc {
+            Nil => {}
+            Append(x, y) => {
+                todo.push((i, *y));
+                todo.push((i, *x));
+            }
+            Nest(j, x) => todo.push((i + j, *x)),
+            Text(s) => k += text_width(&s),
+            Line => return true,
+            Union(x, y) => {
+                choices.push(Choice {
+                    todo_len: todo.len(),
+                    rest,
+                    k,
+                    alt: (i, *y),
+                });
+                todo.push((i, *x));
+            }
+            Annotate(_a, x) => todo.push((i, *x)),
+            Column(f) => todo.push((i, f(k))),
+            Nesting(f) => todo.push((i, f(i))),
+        }
+    }
+}
+
+pub fn pretty<A: Clone>(w: i64, x: DocCore<A>) -> String {
+    let mut buf = Vec::new();
+    render(w, w, &x, &mut buf).expect("writing to a Vec<u8> never fails");
+    String::from_utf8(buf).expect("DocCore text is only ever valid UTF-8")
+}
+
+pub fn pretty_ribbon<A: Clone>(w: i64, ribbon_frac: f64, x: DocCore<A>) -> String {
+    let ribbon = (w as f64 * ribbon_frac).round() as i64;
+    let mut buf = Vec::new();
+    render(w, ribbon, &x, &mut buf).expect("writing to a Vec<u8> never fails");
+    String::from_utf8(buf).expect("DocCore text is only ever valid UTF-8")
+}
+
+// A single rendering decision, in order, with annotation boundaries made
+// explicit: `PushAnn(a)` always has a matching `PopAnn` around exactly the
+// events produced by the annotated subtree, nested correctly regardless of
+// which `Union` alternative `better` picked along the way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotEvent<A> {
+    Text(String),
+    Line(i64),
+    PushAnn(A),
+    PopAnn,
 }
 
-pub fn pretty(w: i64, x: DocCore) -> String {
-    layout(best(w, 0, x))
+pub fn render_annotated<A: Clone>(w: i64, x: DocCore<A>) -> Vec<AnnotEvent<A>> {
+    let mut events = Vec::new();
+    render_annotated_events(best(w, w, 0, x), &mut events);
+    events
+}
+
+fn render_annotated_events<A>(x: Doc<A>, out: &mut Vec<AnnotEvent<A>>) {
+    use Doc::*;
+    match x {
+        Nil => {}
+        Text(s, x) => {
+            out.push(AnnotEvent::Text(s));
+            render_annotated_events(*x, out);
+        }
+        Line(i, x) => {
+            out.push(AnnotEvent::Line(i));
+            render_annotated_events(*x, out);
+        }
+        PushAnn(a, x) => {
+            out.push(AnnotEvent::PushAnn(a));
+            render_annotated_events(*x, out);
+        }
+        PopAnn(x) => {
+            out.push(AnnotEvent::PopAnn);
+            render_annotated_events(*x, out);
+        }
+    }
 }
 
 // TODO: move to utilities module.
@@ -162,6 +573,16 @@ pub fn bracket(l: String, x: DocCore, r: String) -> DocCore {
     ))
 }
 
+// Like `bracket`, but the contents and the closing delimiter line up under
+// the column where the opening delimiter was printed, rather than at a
+// fixed two-space indent.
+pub fn bracket_aligned(l: String, x: DocCore, r: String) -> DocCore {
+    group(append(
+        text(l),
+        align(append(append(line(), x), append(line(), text(r)))),
+    ))
+}
+
 pub fn space_newline(x: DocCore, y: DocCore) -> DocCore {
     append(x, append(append(text(String::from(" ")), line()), y))
 }
@@ -294,4 +715,198 @@ mod test {
     fn show_tree_02() {
         insta::assert_snapshot!(pretty(30, show_tree_prime(tree())));
     }
+
+    #[test]
+    fn cjk_glyphs_count_as_two_columns() {
+        let doc: DocCore = group(append(
+            text(String::from("你好世界")),
+            append(line(), text(String::from("abcd"))),
+        ));
+        // "你好世界" is 4 characters but 8 display columns, so it alone
+        // already exceeds a width of 7 and the group must break.
+        assert_eq!(pretty(7, doc), "你好世界\nabcd");
+    }
+
+    #[test]
+    fn render_matches_best_layout_oracle() {
+        let doc = show_tree(tree());
+        let mut buf = Vec::new();
+        render(30, 30, &doc, &mut buf).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            layout(best(30, 30, 0, doc))
+        );
+    }
+
+    #[test]
+    fn group_followed_by_same_line_text_before_next_break() {
+        // Regression for a `fits_ahead_scan` bug where the pending stack
+        // was scanned bottom-to-top instead of top-to-bottom (the order
+        // `render_stack` itself consumes it in), so a hard `Line` further
+        // down the stack could be reached — and reported as "fits" —
+        // before the "zzz" between the group and that line was ever
+        // measured, wrongly keeping the group flat past the page width.
+        let doc: DocCore = append(
+            append(group(line()), text(String::from("zzz"))),
+            append(line(), text(String::from("x"))),
+        );
+        assert_eq!(pretty(1, doc), "\nzzz\nx");
+    }
+
+    // A small xorshift64 generator, good enough for deterministic
+    // pseudo-random test data without pulling in an external rand
+    // dependency.
+    struct Xorshift64(u64);
+
+    impl Xorshift64 {
+        fn next(&mut self) -> u64 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            self.0 = x;
+            x
+        }
+
+        fn below(&mut self, n: u64) -> u64 {
+            self.next() % n
+        }
+    }
+
+    // Builds a pseudo-random `DocCore` mixing text, hard/soft line breaks
+    // and groups (including a group immediately followed by same-line
+    // text, the shape that exposed the `fits_ahead_scan` stack-order
+    // bug), so `render_matches_best_layout_oracle_fuzz` below isn't
+    // limited to the single hand-written `show_tree` example.
+    fn arbitrary_doc(rng: &mut Xorshift64, depth: i64) -> DocCore {
+        if depth <= 0 {
+            return text(String::from(
+                ["a", "bb", "ccc", "dddd"][rng.below(4) as usize],
+            ));
+        }
+        match rng.below(6) {
+            0 => nil(),
+            1 => text(String::from(
+                ["x", "yy", "zzz", "wwww", "v"][rng.below(5) as usize],
+            )),
+            2 => line(),
+            3 => append(arbitrary_doc(rng, depth - 1), arbitrary_doc(rng, depth - 1)),
+            4 => nest(rng.below(4) as i64, arbitrary_doc(rng, depth - 1)),
+            _ => group(arbitrary_doc(rng, depth - 1)),
+        }
+    }
+
+    #[test]
+    fn render_matches_best_layout_oracle_fuzz() {
+        let mut rng = Xorshift64(0x9e3779b97f4a7c15);
+        for case in 0..2000 {
+            let doc: DocCore = arbitrary_doc(&mut rng, 5);
+            let w = 1 + rng.below(12) as i64;
+            // Exercise ribbon both equal to and narrower than the page width.
+            let ribbon = 1 + rng.below(w as u64) as i64;
+            let mut buf = Vec::new();
+            render(w, ribbon, &doc, &mut buf).unwrap();
+            let got = String::from_utf8(buf).unwrap();
+            let want = layout(best(w, ribbon, 0, doc));
+            assert_eq!(got, want, "case {case}: w={w} ribbon={ribbon}");
+        }
+    }
+
+    #[test]
+    fn align_tracks_cursor_column_not_fixed_nest() {
+        let doc: DocCore = append(
+            text(String::from("let x = ")),
+            align(append(
+                text(String::from("1")),
+                append(line(), text(String::from("+ 2"))),
+            )),
+        );
+        // The continuation line lines up under the "1", i.e. at column 8
+        // (the width of "let x = "), not at some fixed nest offset.
+        assert_eq!(pretty(4, doc), "let x = 1\n        + 2");
+    }
+
+    #[test]
+    fn indent_lines_up_continuation_under_the_indent_not_the_start_column() {
+        let doc: DocCore = indent(
+            4,
+            append(text(String::from("aaa")), append(line(), text(String::from("bbb")))),
+        );
+        // The continuation line sits under the indented "aaa", 4 columns in,
+        // not back at column 0 where `indent` itself started.
+        assert_eq!(pretty(3, doc), "    aaa\n    bbb");
+    }
+
+    #[test]
+    fn bracket_aligned_lines_up_under_opening_delimiter() {
+        let doc: DocCore = append(
+            text(String::from("items = ")),
+            bracket_aligned(
+                String::from("["),
+                append(
+                    text(String::from("aaaa")),
+                    append(text(String::from(",")), append(line(), text(String::from("bbbb")))),
+                ),
+                String::from("]"),
+            ),
+        );
+        assert_eq!(pretty(10, doc), "items = [\n         aaaa,\n         bbbb\n         ]");
+    }
+
+    fn deeply_indented() -> DocCore {
+        append(
+            text(String::from("outerhead")),
+            nest(
+                20,
+                append(
+                    line(),
+                    group(append(
+                        text(String::from("abcdefg")),
+                        append(line(), text(String::from("hijklmn"))),
+                    )),
+                ),
+            ),
+        )
+    }
+
+    #[test]
+    fn pretty_keeps_wide_indented_group_flat() {
+        // Plenty of width and ribbon at column 20: the group stays flat.
+        assert_eq!(
+            pretty(40, deeply_indented()),
+            format!("outerhead\n{}abcdefg hijklmn", " ".repeat(20))
+        );
+    }
+
+    #[test]
+    fn pretty_ribbon_breaks_group_past_ribbon_limit() {
+        // Same page width, but a ribbon of 12 can't hold "abcdefg hijklmn"
+        // (15 columns) past the 20-column indent, so the group breaks even
+        // though it would easily fit the page width alone.
+        assert_eq!(
+            pretty_ribbon(40, 0.3, deeply_indented()),
+            format!(
+                "outerhead\n{indent}abcdefg\n{indent}hijklmn",
+                indent = " ".repeat(20)
+            )
+        );
+    }
+
+    #[test]
+    fn annotate_nests_push_pop_across_union() {
+        let doc = group(annotate(
+            "bold",
+            append(text(String::from("aaa")), append(line(), text(String::from("bbb")))),
+        ));
+        assert_eq!(
+            render_annotated(5, doc),
+            vec![
+                AnnotEvent::PushAnn("bold"),
+                AnnotEvent::Text(String::from("aaa")),
+                AnnotEvent::Line(0),
+                AnnotEvent::Text(String::from("bbb")),
+                AnnotEvent::PopAnn,
+            ]
+        );
+    }
 }